@@ -1,12 +1,49 @@
 //! Defines some utilities that can be used to write the CI pipelines of Rust projects.
 
-use anyhow::{Context, Result, anyhow};
+use anyhow::{Context as _, Result, anyhow};
 use clap::{Parser, ValueEnum};
-use std::{fmt, fs, path::Path, process::Command};
+use std::{
+    fmt, fs,
+    path::{Path, PathBuf},
+    process::Command,
+    sync::Mutex,
+    thread,
+};
 
 // Re-exported so that clients can use and modify [`Cli`] using a compatible version of [`clap`].
 pub use clap;
 
+/// Controls whether [`execute_command_with`] actually spawns the command it's given.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DryRun {
+    /// Spawn the command as usual.
+    #[default]
+    No,
+    /// Don't spawn the command. Log the fully-rendered command line at [`INFO`][tracing::Level::INFO] instead and report success.
+    Yes,
+}
+
+/// Controls whether [`execute_command_with`] surfaces a command's output when it succeeds.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OutputMode {
+    /// Always surface the command's output, regardless of whether it succeeded.
+    #[default]
+    Always,
+    /// Only surface the command's output if it fails. This keeps long, noisy but otherwise successful commands (e.g. `cargo doc`) out of the logs, while preserving full diagnostics when something breaks.
+    OnFailure,
+}
+
+/// Options that control how [`execute_command_with`] runs a command.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CommandOptions<'a> {
+    pub dry_run: DryRun,
+    /// When set, the command's output is appended here instead of being logged immediately.
+    ///
+    /// This lets a caller that runs several commands as part of one larger unit of work (e.g. a [`Step`]) flush their combined output atomically once that unit of work finishes, which keeps the output of concurrently-running units from interleaving. See [`run_steps`].
+    pub log_buffer: Option<&'a Mutex<String>>,
+    pub output_mode: OutputMode,
+}
+
 /// Executes a command and returns an error if it fails.
 ///
 /// # Parameters
@@ -19,6 +56,34 @@ pub use clap;
 /// # Panics
 /// Panics if the command can't be executed.
 pub fn execute_command(command: &mut Command, description: &str) -> Option<()> {
+    execute_command_with(&CommandOptions::default(), command, description)
+}
+
+/// Like [`execute_command`], but lets the caller customize how the command is run through `options`.
+///
+/// # Parameters
+/// - `options` controls how the command is run. See [`CommandOptions`] for the available settings.
+/// - `command` is the command that has to be executed.
+/// - `description` is the description of the command. It should be a complete sentence, that is it should start with a capital letter and end with a period.
+///
+/// # Returns
+/// Returns [`None`] if and only if it fails. When `options.dry_run` is [`DryRun::Yes`], the command isn't spawned and this always succeeds.
+///
+/// # Panics
+/// Panics if the command can't be executed.
+pub fn execute_command_with(
+    options: &CommandOptions,
+    command: &mut Command,
+    description: &str,
+) -> Option<()> {
+    if options.dry_run == DryRun::Yes {
+        emit_dry_run_preview(
+            options,
+            &format!("{}\nDry run, would execute: {:?}", description, command),
+        );
+        return Some(());
+    }
+
     let output = command.output().unwrap_or_else(|_| {
         panic!("Failed to execute the command `{:?}`", command);
     });
@@ -31,19 +96,63 @@ pub fn execute_command(command: &mut Command, description: &str) -> Option<()> {
         log.push('\n');
         log.push_str(&String::from_utf8_lossy(&output.stderr));
     };
-    tracing::info!("{}", log);
-    if output.status.success() {
-        Some(())
-    } else {
-        tracing::error!("Failed");
+    let failed = !output.status.success();
+    emit(options, &log, failed);
+    if failed {
         None
+    } else {
+        Some(())
+    }
+}
+
+/// Like [`emit`], but always surfaces `log` regardless of `options.output_mode`.
+///
+/// Used for the dry run preview, which should be visible no matter what `output_mode` a step was configured with; otherwise an [`OutputMode::OnFailure`] step would preview nothing at all, defeating the point of [`DryRun`].
+fn emit_dry_run_preview(options: &CommandOptions, log: &str) {
+    match options.log_buffer {
+        Some(buffer) => {
+            let mut buffer = buffer.lock().unwrap();
+            if !buffer.is_empty() {
+                buffer.push('\n');
+            }
+            buffer.push_str(log);
+        }
+        None => tracing::info!("{}", log),
+    }
+}
+
+/// Logs `log`, or appends it to `options.log_buffer` if set. Suppresses `log` itself (but not the `Failed` marker) when `options.output_mode` is [`OutputMode::OnFailure`] and `failed` is `false`. Logs at [`ERROR`][tracing::Level::ERROR] when `failed` is `true`.
+fn emit(options: &CommandOptions, log: &str, failed: bool) {
+    let surface_output = failed || options.output_mode == OutputMode::Always;
+    match options.log_buffer {
+        Some(buffer) => {
+            let mut buffer = buffer.lock().unwrap();
+            if surface_output {
+                if !buffer.is_empty() {
+                    buffer.push('\n');
+                }
+                buffer.push_str(log);
+            }
+            if failed {
+                buffer.push_str("\nFailed");
+            }
+        }
+        None => {
+            if failed {
+                tracing::error!("{}\nFailed", log);
+            } else if surface_output {
+                tracing::info!("{}", log);
+            }
+        }
     }
 }
 
 /// Checks the formatting of all the files whose paths are passed in input.
 ///
 /// Fails if there are any formatting errors in any of the examinated files. The type of each file is determined based on its extension.
-pub fn format_files<P, Paths>(files: Paths) -> Option<()>
+///
+/// When `log_buffer` is set, the output is appended there instead of being logged immediately. See [`CommandOptions::log_buffer`].
+pub fn format_files<P, Paths>(files: Paths, log_buffer: Option<&Mutex<String>>) -> Option<()>
 where
     P: AsRef<Path>,
     Paths: IntoIterator<Item = P>,
@@ -54,20 +163,20 @@ where
         let file_path = file_path.as_ref();
         let file_type = FormatType::parse(file_path)
             .inspect_err(|e| {
-                tracing::error!("{:?}", e);
+                log_or_buffer(log_buffer, &format!("{:?}", e), /* failed */ true);
             })
             .ok()?;
         let file_contents = fs::read_to_string(file_path)
             .with_context(|| format!("Couldn't read the contents of '{}'.", file_path.display()))
             .inspect_err(|e| {
-                tracing::error!("{:?}", e);
+                log_or_buffer(log_buffer, &format!("{:?}", e), /* failed */ true);
             })
             .ok()?;
         let formatted = file_type
             .format(&file_contents)
             .with_context(|| format!("Couldn't format the contents of '{}'.", file_path.display()))
             .inspect_err(|e| {
-                tracing::error!("{:?}", e);
+                log_or_buffer(log_buffer, &format!("{:?}", e), /* failed */ true);
             })
             .ok()?;
         if formatted != file_contents {
@@ -79,21 +188,51 @@ where
                     )
                 })
                 .inspect_err(|e| {
-                    tracing::error!("{:?}", e);
+                    log_or_buffer(log_buffer, &format!("{:?}", e), /* failed */ true);
                 })
                 .ok()?;
-            tracing::error!(
-                "'{}' wasn't formatted correctly and has been formatted.",
-                file_path.display()
+            log_or_buffer(
+                log_buffer,
+                &format!(
+                    "'{}' wasn't formatted correctly and has been formatted.",
+                    file_path.display()
+                ),
+                /* failed */ true,
             );
             return None;
         }
     }
 
-    tracing::info!("All the files are formatted correctly.");
+    log_or_buffer(
+        log_buffer,
+        "All the files are formatted correctly.",
+        /* failed */ false,
+    );
     Some(())
 }
 
+/// Logs `message`, or appends it to `log_buffer` if set. Logs at [`ERROR`][tracing::Level::ERROR] when `failed` is `true`, at [`INFO`][tracing::Level::INFO] otherwise.
+///
+/// Used by functions like [`format_files`] that don't otherwise go through [`execute_command_with`] and therefore can't use [`CommandOptions`].
+fn log_or_buffer(log_buffer: Option<&Mutex<String>>, message: &str, failed: bool) {
+    match log_buffer {
+        Some(buffer) => {
+            let mut buffer = buffer.lock().unwrap();
+            if !buffer.is_empty() {
+                buffer.push('\n');
+            }
+            buffer.push_str(message);
+        }
+        None => {
+            if failed {
+                tracing::error!("{}", message);
+            } else {
+                tracing::info!("{}", message);
+            }
+        }
+    }
+}
+
 /// Used to specify which file type has to be formatted.
 enum FormatType {
     Toml,
@@ -168,18 +307,75 @@ impl fmt::Display for FormatType {
 /// This function is basically a wrapper arount a call to [`cargo-machete`]. If [`cargo-machete`] is not present, it will be installed. The installed version is pinned and is not configurable for simplicity.
 ///
 /// [`cargo-machete`]: https://crates.io/crates/cargo-machete
-pub fn check_for_unused_deps() -> Option<()> {
+pub fn check_for_unused_deps(options: &CommandOptions) -> Option<()> {
     let _span = tracing::error_span!("Dependencies").entered();
-    execute_command(
+    execute_command_with(
+        options,
         Command::new("cargo").args(["install", "cargo-machete@0.9.1", "--locked"]),
         "Installing `cargo-machete`.",
     )?;
-    execute_command(
+    execute_command_with(
+        options,
         Command::new("cargo-machete").args(["--with-metadata"]),
         "Checking for unused dependencies.",
     )
 }
 
+/// Checks for known security advisories, banned dependencies, license compliance issues and disallowed registries.
+///
+/// This function is basically a wrapper around a call to [`cargo-deny`]. If [`cargo-deny`] is not present, it will be installed. The installed version is pinned and is not configurable for simplicity.
+///
+/// # Parameters
+/// - `advisories_fatal` controls whether a failure of the `advisories` check causes this function to fail. Passing `false` keeps a freshly announced RustSec advisory from failing an unrelated PR (the check's output is still logged), while the `bans`, `licenses` and `sources` checks stay fatal regardless.
+///
+/// [`cargo-deny`]: https://crates.io/crates/cargo-deny
+pub fn check_security_advisories(advisories_fatal: bool, options: &CommandOptions) -> Option<()> {
+    let _span = tracing::error_span!("Security advisories").entered();
+    execute_command_with(
+        options,
+        Command::new("cargo").args(["install", "cargo-deny@0.16.3", "--locked"]),
+        "Installing `cargo-deny`.",
+    )?;
+
+    {
+        let _span = tracing::error_span!("advisories").entered();
+        let result = execute_command_with(
+            options,
+            Command::new("cargo-deny").args(["check", "advisories"]),
+            "Checking for security advisories.",
+        );
+        if result.is_none() && advisories_fatal {
+            return None;
+        }
+    }
+    {
+        let _span = tracing::error_span!("bans").entered();
+        execute_command_with(
+            options,
+            Command::new("cargo-deny").args(["check", "bans"]),
+            "Checking for banned dependencies.",
+        )?;
+    }
+    {
+        let _span = tracing::error_span!("licenses").entered();
+        execute_command_with(
+            options,
+            Command::new("cargo-deny").args(["check", "licenses"]),
+            "Checking license compliance.",
+        )?;
+    }
+    {
+        let _span = tracing::error_span!("sources").entered();
+        execute_command_with(
+            options,
+            Command::new("cargo-deny").args(["check", "sources"]),
+            "Checking for disallowed dependency sources.",
+        )?;
+    }
+
+    Some(())
+}
+
 /// Used to control whether the different packages of the workspace share the same [target directory](https://doc.rust-lang.org/nightly/cargo/reference/build-cache.html) or not.
 ///
 /// [Sharing][Self::Shared] is the standard behaviour and it allows Cargo to cache compilations. This reduces the final size of the target directory and reduces build times, given that Cargo can reuse the compiled artificats for the shared dependencies. The problem with sharing is that some dependencies have to be recompiled when checking the different packages that belong to the workspace, a fact that slows down CI on repeated runs.
@@ -213,4 +409,171 @@ pub struct Cli {
     /// See the documentation of [`TargetDirType`] for more information.
     #[arg(short, long, default_value = "shared")]
     pub target_dir_type: TargetDirType,
+
+    /// Logs the commands that the pipeline would run, without actually running them.
+    #[arg(long)]
+    pub dry_run: bool,
+
+    /// Paths whose steps should run. When empty, every default step runs.
+    ///
+    /// A step runs if any of the paths it declares through [`Step::paths`] is a prefix of one of these paths.
+    pub paths: Vec<PathBuf>,
+
+    /// The maximum number of non-[exclusive][Step::exclusive] [`Step`]s [`run_steps`] runs concurrently. Exclusive steps run one at a time on a lane of their own, in addition to these.
+    #[arg(short = 'j', long, default_value_t = default_jobs())]
+    pub jobs: usize,
+}
+
+/// Returns the number of logical CPUs available, or `1` if it can't be determined.
+fn default_jobs() -> usize {
+    thread::available_parallelism().map(|n| n.get()).unwrap_or(1)
+}
+
+/// Shared state threaded through every [`Step`] of a pipeline.
+#[derive(Clone, Copy)]
+pub struct Context<'a> {
+    /// Controls whether the packages checked by the pipeline share a [target directory][TargetDirType] or not.
+    pub target_dir_type: TargetDirType,
+    /// Controls whether the steps actually run their commands. See [`DryRun`] for details.
+    pub dry_run: DryRun,
+    /// The maximum number of non-[exclusive][Step::exclusive] steps [`run_steps`] runs concurrently. Exclusive steps run one at a time on a lane of their own, in addition to these.
+    pub jobs: usize,
+    /// When set, the commands run by the current step should append their output here instead of logging it immediately. Set by [`run_buffered`] for the duration of its `body`; [`None`] otherwise.
+    pub log_buffer: Option<&'a Mutex<String>>,
+}
+
+/// A single unit of work in a CI pipeline.
+///
+/// Implementors declare the paths they check through [`Step::paths`], so that [`run_steps`] can skip steps that are unrelated to the paths the caller is interested in. [`Step`] requires [`Send`] and [`Sync`] because [`run_steps`] runs steps concurrently across threads.
+pub trait Step: Send + Sync {
+    /// A short, human-readable name for this step, used for logging.
+    fn name(&self) -> &str;
+
+    /// The paths, relative to the repository root, that this step checks.
+    fn paths(&self) -> &[&str];
+
+    /// Returns `true` if this step should run given the paths that changed.
+    ///
+    /// Returns `true` unconditionally if `changed` is empty, meaning that every default step should run. Otherwise, returns `true` if any of [`Step::paths`] is a prefix of one of the paths in `changed`.
+    fn should_run(&self, changed: &[PathBuf]) -> bool {
+        changed.is_empty()
+            || changed.iter().any(|changed_path| {
+                self.paths()
+                    .iter()
+                    .any(|step_path| changed_path.starts_with(step_path))
+            })
+    }
+
+    /// Returns `true` if this step mutates shared state (e.g. rewriting files in place), and therefore must not run concurrently with other exclusive steps.
+    ///
+    /// Defaults to `false`.
+    fn exclusive(&self) -> bool {
+        false
+    }
+
+    /// Runs the step.
+    ///
+    /// # Returns
+    /// Returns [`None`] if and only if it fails.
+    fn run(&self, ctx: &Context) -> Option<()>;
+}
+
+/// Splits the steps in `steps` whose [`Step::should_run`] returns `true` for `changed` into the exclusive and non-exclusive ones, in that order.
+fn partition_steps_to_run<'a>(
+    steps: &'a [Box<dyn Step>],
+    changed: &[PathBuf],
+) -> (Vec<&'a dyn Step>, Vec<&'a dyn Step>) {
+    steps
+        .iter()
+        .map(Box::as_ref)
+        .filter(|step| step.should_run(changed))
+        .partition(|step| step.exclusive())
+}
+
+/// Returns `true` if [`run_steps`] could run more than one step of `steps` at the same time, given `changed` and `jobs`.
+///
+/// There's no overlap only when there's nothing to run concurrently with: either there are no [exclusive][Step::exclusive] steps to run and at most one concurrent one can run at a time, or there's nothing to run concurrently alongside the (at most one, by construction) running exclusive step.
+///
+/// Useful to decide whether two steps' spans might be entered and exited concurrently, e.g. to set up [`GhaLayer::with_group_spans`](crate::logging::GhaLayer::with_group_spans) correctly.
+pub fn may_run_concurrently(steps: &[Box<dyn Step>], changed: &[PathBuf], jobs: usize) -> bool {
+    let (exclusive, concurrent) = partition_steps_to_run(steps, changed);
+    let exclusive_lanes = usize::from(!exclusive.is_empty());
+    let concurrent_lanes = jobs.max(1).min(concurrent.len());
+    exclusive_lanes + concurrent_lanes > 1
+}
+
+/// Runs every step in `steps` whose [`Step::should_run`] returns `true` for `changed`.
+///
+/// Non-[exclusive][Step::exclusive] steps run concurrently, up to `ctx.jobs` at a time. Exclusive steps run one at a time on a dedicated lane, so that two of them never overlap, while still running alongside the concurrent steps. This function doesn't buffer the steps' output itself: a step whose [`Step::run`] goes through [`run_buffered`] has its output flushed atomically once it finishes, so that concurrently-running steps' logs don't interleave.
+///
+/// # Returns
+/// Returns [`None`] if and only if one of the steps that ran failed.
+pub fn run_steps(steps: &[Box<dyn Step>], changed: &[PathBuf], ctx: &Context) -> Option<()> {
+    let (exclusive, concurrent) = partition_steps_to_run(steps, changed);
+
+    let exclusive_queue = Mutex::new(exclusive.into_iter());
+    let concurrent_queue = Mutex::new(concurrent.into_iter());
+    let jobs = ctx.jobs.max(1);
+
+    let any_failed = thread::scope(|scope| {
+        let mut handles = Vec::with_capacity(jobs + 1);
+
+        handles.push(scope.spawn(|| {
+            let mut failed = false;
+            while let Some(step) = exclusive_queue.lock().unwrap().next() {
+                if step.run(ctx).is_none() {
+                    failed = true;
+                }
+            }
+            failed
+        }));
+        for _ in 0..jobs {
+            handles.push(scope.spawn(|| {
+                let mut failed = false;
+                while let Some(step) = concurrent_queue.lock().unwrap().next() {
+                    if step.run(ctx).is_none() {
+                        failed = true;
+                    }
+                }
+                failed
+            }));
+        }
+
+        let mut any_failed = false;
+        for handle in handles {
+            if handle.join().unwrap() {
+                any_failed = true;
+            }
+        }
+        any_failed
+    });
+
+    if any_failed {
+        None
+    } else {
+        Some(())
+    }
+}
+
+/// Runs `body` with a fresh log buffer installed as `ctx.log_buffer`, then flushes whatever it wrote through `tracing` once `body` returns, at [`ERROR`][tracing::Level::ERROR] if `body` failed and at [`INFO`][tracing::Level::INFO] otherwise.
+///
+/// A [`Step::run`] implementation should call this itself, after entering its own span, and run its actual work inside `body`. `tracing`'s span names have to be string literals, so [`run_steps`] can't open a step's span on its behalf; flushing here rather than in [`run_steps`] keeps the output attributed to that span instead of leaking out after it's been exited.
+pub fn run_buffered(ctx: &Context, body: impl FnOnce(&Context) -> Option<()>) -> Option<()> {
+    let log_buffer = Mutex::new(String::new());
+    let step_ctx = Context {
+        log_buffer: Some(&log_buffer),
+        ..*ctx
+    };
+
+    let result = body(&step_ctx);
+
+    let log = log_buffer.into_inner().unwrap();
+    if !log.is_empty() {
+        if result.is_some() {
+            tracing::info!("{}", log);
+        } else {
+            tracing::error!("{}", log);
+        }
+    }
+    result
 }
@@ -2,8 +2,11 @@
 //!
 //! The main item of this module is the [`init_logger`] function. See its documentation for an explanation of how it configures the logging.
 
+use std::{env, fmt::Write as _};
+use tracing::{Event, Level, Subscriber, span};
 use tracing_subscriber::{
-    EnvFilter, filter::LevelFilter, layer::SubscriberExt, registry::Registry,
+    EnvFilter, Layer, filter::LevelFilter, layer::Context as LayerContext, layer::SubscriberExt,
+    registry::{LookupSpan, Registry},
 };
 use tracing_tree::HierarchicalLayer;
 
@@ -25,13 +28,130 @@ pub fn init_env_filter() -> EnvFilter {
         .from_env_lossy()
 }
 
+/// Returns `true` if the code is currently running inside a GitHub Actions workflow.
+///
+/// Detection is based on the presence of the `GITHUB_ACTIONS` or `CI` environment variables, both of which GitHub Actions [sets on every run](https://docs.github.com/en/actions/learn-github-actions/variables#default-environment-variables).
+fn running_in_github_actions() -> bool {
+    env::var_os("GITHUB_ACTIONS").is_some() || env::var_os("CI").is_some()
+}
+
+/// A [`Layer`] that translates spans and events into [GitHub Actions workflow commands](https://docs.github.com/en/actions/using-workflows/workflow-commands-for-github-actions), so that CI logs fold per-step and failures are annotated inline in the pull request diff.
+///
+/// Entering a span prints `::group::<name>` and exiting it prints `::endgroup::`, unless grouping has been disabled through [`with_group_spans`][Self::with_group_spans]. Events logged at [`ERROR`][Level::ERROR] are translated into `::error file=...,line=...::<message>` annotations, and events logged at [`WARN`][Level::WARN] into `::warning file=...,line=...::<message>` annotations.
+#[derive(Clone, Copy, Debug)]
+pub struct GhaLayer {
+    group_spans: bool,
+}
+
+impl Default for GhaLayer {
+    fn default() -> Self {
+        Self { group_spans: true }
+    }
+}
+
+impl GhaLayer {
+    /// Controls whether entering/exiting a span emits `::group::`/`::endgroup::` workflow commands. Enabled by default.
+    ///
+    /// Disable this when spans might be entered and exited concurrently, for instance when [`run_steps`](crate::ci::run_steps) runs more than one step at a time: GitHub Actions folds groups by treating `::group::`/`::endgroup::` as a single stack shared across the whole log, so markers emitted from different threads interleave and produce incorrectly nested folds.
+    pub fn with_group_spans(mut self, group_spans: bool) -> Self {
+        self.group_spans = group_spans;
+        self
+    }
+}
+
+impl<S> Layer<S> for GhaLayer
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    fn on_enter(&self, id: &span::Id, ctx: LayerContext<'_, S>) {
+        if !self.group_spans {
+            return;
+        }
+        if let Some(span) = ctx.span(id) {
+            println!("::group::{}", span.name());
+        }
+    }
+
+    fn on_exit(&self, _id: &span::Id, _ctx: LayerContext<'_, S>) {
+        if self.group_spans {
+            println!("::endgroup::");
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, _ctx: LayerContext<'_, S>) {
+        let level = *event.metadata().level();
+        let command = match level {
+            Level::ERROR => "error",
+            Level::WARN => "warning",
+            _ => return,
+        };
+
+        let mut message = String::new();
+        event.record(&mut MessageVisitor(&mut message));
+
+        // `file`/`line` are those of the `tracing::error!`/`tracing::warn!` call site that logged
+        // the event. They're omitted when the event's metadata doesn't record them.
+        let metadata = event.metadata();
+        match (metadata.file(), metadata.line()) {
+            (Some(file), Some(line)) => println!(
+                "::{command} file={},line={line}::{}",
+                escape_for_workflow_command(file)
+                    .replace(':', "%3A")
+                    .replace(',', "%2C"),
+                escape_for_workflow_command(&message)
+            ),
+            _ => println!("::{command}::{}", escape_for_workflow_command(&message)),
+        }
+    }
+}
+
+/// Escapes `message` so it can be embedded as the data of a single [GitHub Actions workflow command](https://docs.github.com/en/actions/using-workflows/workflow-commands-for-github-actions#escaping-data-and-properties).
+///
+/// Without this, embedded newlines (e.g. from a buffered, multi-line step log) would end the workflow command early, truncating the annotation to its first line.
+fn escape_for_workflow_command(message: &str) -> String {
+    message
+        .replace('%', "%25")
+        .replace('\r', "%0D")
+        .replace('\n', "%0A")
+}
+
+/// Collects the `message` field of a [`tracing`] event into a [`String`].
+struct MessageVisitor<'a>(&'a mut String);
+
+impl tracing::field::Visit for MessageVisitor<'_> {
+    fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+        if field.name() == "message" {
+            let _ = write!(self.0, "{:?}", value);
+        }
+    }
+}
+
+/// Returns a [`GhaLayer`] that translates `tracing` spans and events into GitHub Actions workflow commands.
+///
+/// Use this directly if you're building your own subscriber; [`init_logger`] already composes it automatically when running inside GitHub Actions.
+pub fn init_gha_log_layer() -> GhaLayer {
+    GhaLayer::default()
+}
+
 /// Sets up the logging.
 ///
 /// # Details
 /// The logs are printed to the terminal. The log level and filtering strategy can be set through the `RUST_LOG` environment variable, as explained [here][EnvFilter]. By default, all the events whose [`Level`][tracing::Level] is [`INFO`][tracing::Level::INFO] or higher are logged.
+///
+/// When running inside a GitHub Actions workflow, an additional [`init_gha_log_layer`] is composed in, so that spans fold into collapsible `::group::` sections and `ERROR`/`WARN` events are surfaced as inline annotations.
 pub fn init_logger() {
+    init_logger_with(/* group_spans */ true)
+}
+
+/// Like [`init_logger`], but lets the caller control whether the GitHub Actions layer groups spans.
+///
+/// Pass `false` when spans might be entered and exited concurrently, for instance when [`run_steps`](crate::ci::run_steps) runs more than one step at a time; see [`GhaLayer::with_group_spans`] for why concurrent grouping produces incorrectly nested folds.
+pub fn init_logger_with(group_spans: bool) {
+    let gha_layer =
+        running_in_github_actions().then(|| GhaLayer::default().with_group_spans(group_spans));
     let subscriber = Registry::default()
         .with(init_env_filter())
-        .with(init_std_out_log_formatter());
+        .with(init_std_out_log_formatter())
+        .with(gha_layer);
     tracing::subscriber::set_global_default(subscriber).unwrap();
 }
@@ -3,60 +3,231 @@
 //! For more information see the *Continuous Integration* section of the `README`.
 
 use rust_utils::{
-    ci::{check_for_unused_deps, execute_command, format_files},
-    logging::init_logger,
+    ci::{
+        check_for_unused_deps, check_security_advisories, clap::Parser, execute_command_with,
+        format_files, may_run_concurrently, run_buffered, run_steps, Cli, CommandOptions, Context,
+        DryRun, OutputMode, Step,
+    },
+    logging::init_logger_with,
 };
 
 use std::process::Command;
 
 fn main() -> Result<(), ()> {
-    init_logger();
-    run_ci_pipeline().ok_or(())
+    let cli = Cli::parse();
+    let steps = steps();
+    // GitHub Actions folds `::group::`/`::endgroup::` as a single stack shared across the whole
+    // log, so grouping has to be disabled whenever more than one step could actually run at once.
+    init_logger_with(!may_run_concurrently(&steps, &cli.paths, cli.jobs));
+    let ctx = Context {
+        target_dir_type: cli.target_dir_type,
+        dry_run: if cli.dry_run { DryRun::Yes } else { DryRun::No },
+        jobs: cli.jobs,
+        log_buffer: None,
+    };
+    run_steps(&steps, &cli.paths, &ctx).ok_or(())
 }
 
-/// Runs the commands that compose the CI pipeline.
-///
-/// # Returns
-/// Returns [`None`] if and only if it fails.
-fn run_ci_pipeline() -> Option<()> {
-    run_ci_pipeline_rust_utils()?;
-    check_for_unused_deps()?;
-    format_files([
-        ".cargo/config.toml",
-        "Cargo.toml",
-        "rust-toolchain.toml",
-        ".github/workflows/ci.yml",
-        ".github/dependabot.yml",
-    ])
-}
-
-/// Runs the commands that compose the CI pipeline for the [`rust-utils`][rust_utils] package.
+/// Returns the steps that compose the CI pipeline for this repository.
+fn steps() -> Vec<Box<dyn Step>> {
+    vec![
+        Box::new(Clippy),
+        Box::new(Docs),
+        Box::new(Fmt),
+        Box::new(Machete),
+        Box::new(Deny),
+        Box::new(FormatConfigs),
+    ]
+}
+
+/// Runs `cargo clippy` against the `rust-utils` package.
+struct Clippy;
+
+impl Step for Clippy {
+    fn name(&self) -> &str {
+        "Clippy"
+    }
+
+    fn paths(&self) -> &[&str] {
+        &["src", "Cargo.toml"]
+    }
+
+    fn run(&self, ctx: &Context) -> Option<()> {
+        let _span = tracing::error_span!("Clippy").entered();
+        run_buffered(ctx, |ctx| {
+            let options = CommandOptions {
+                dry_run: ctx.dry_run,
+                log_buffer: ctx.log_buffer,
+                output_mode: OutputMode::Always,
+            };
+            execute_command_with(
+                &options,
+                Command::new("cargo")
+                    .args(["clippy", "--package", "rust-utils"])
+                    .env(
+                        "CARGO_TARGET_DIR",
+                        ctx.target_dir_type.get_target_dir_path(self.name()),
+                    ),
+                "Checking the source code",
+            )
+        })
+    }
+}
+
+/// Generates the public and private documentation of the `rust-utils` package.
+struct Docs;
+
+impl Step for Docs {
+    fn name(&self) -> &str {
+        "Docs"
+    }
+
+    fn paths(&self) -> &[&str] {
+        &["src", "Cargo.toml"]
+    }
+
+    fn run(&self, ctx: &Context) -> Option<()> {
+        let _span = tracing::error_span!("Docs").entered();
+        run_buffered(ctx, |ctx| {
+            let options = CommandOptions {
+                dry_run: ctx.dry_run,
+                log_buffer: ctx.log_buffer,
+                output_mode: OutputMode::OnFailure,
+            };
+            execute_command_with(
+                &options,
+                Command::new("cargo")
+                    .args(["doc", "--package", "rust-utils"])
+                    .env(
+                        "CARGO_TARGET_DIR",
+                        ctx.target_dir_type.get_target_dir_path(self.name()),
+                    ),
+                "Generating the public documentation.",
+            )?;
+            execute_command_with(
+                &options,
+                Command::new("cargo")
+                    .args(["doc", "--package", "rust-utils", "--document-private-items"])
+                    .env(
+                        "CARGO_TARGET_DIR",
+                        ctx.target_dir_type.get_target_dir_path(self.name()),
+                    ),
+                "Generating the private documentation.",
+            )
+        })
+    }
+}
+
+/// Checks the formatting of the `rust-utils` package's source code.
+struct Fmt;
+
+impl Step for Fmt {
+    fn name(&self) -> &str {
+        "Fmt"
+    }
+
+    fn paths(&self) -> &[&str] {
+        &["src", "Cargo.toml"]
+    }
+
+    fn run(&self, ctx: &Context) -> Option<()> {
+        let _span = tracing::error_span!("Fmt").entered();
+        run_buffered(ctx, |ctx| {
+            let options = CommandOptions {
+                dry_run: ctx.dry_run,
+                log_buffer: ctx.log_buffer,
+                output_mode: OutputMode::Always,
+            };
+            execute_command_with(
+                &options,
+                Command::new("cargo")
+                    .args(["fmt", "--check", "--package", "rust-utils"])
+                    .env(
+                        "CARGO_TARGET_DIR",
+                        ctx.target_dir_type.get_target_dir_path(self.name()),
+                    ),
+                "Checking the formatting of the code.",
+            )
+        })
+    }
+}
+
+/// Checks for unused dependencies with `cargo-machete`.
+struct Machete;
+
+impl Step for Machete {
+    fn name(&self) -> &str {
+        "Machete"
+    }
+
+    fn paths(&self) -> &[&str] {
+        &["Cargo.toml"]
+    }
+
+    fn run(&self, ctx: &Context) -> Option<()> {
+        let _span = tracing::error_span!("Machete").entered();
+        run_buffered(ctx, |ctx| {
+            let options = CommandOptions {
+                dry_run: ctx.dry_run,
+                log_buffer: ctx.log_buffer,
+                output_mode: OutputMode::Always,
+            };
+            check_for_unused_deps(&options)
+        })
+    }
+}
+
+/// Checks security advisories, banned dependencies, license compliance and dependency sources with `cargo-deny`.
+struct Deny;
+
+impl Step for Deny {
+    fn name(&self) -> &str {
+        "Deny"
+    }
+
+    fn paths(&self) -> &[&str] {
+        &["Cargo.toml"]
+    }
+
+    fn run(&self, ctx: &Context) -> Option<()> {
+        let _span = tracing::error_span!("Deny").entered();
+        run_buffered(ctx, |ctx| {
+            let options = CommandOptions {
+                dry_run: ctx.dry_run,
+                log_buffer: ctx.log_buffer,
+                output_mode: OutputMode::Always,
+            };
+            check_security_advisories(/* advisories_fatal */ false, &options)
+        })
+    }
+}
+
+/// Checks the formatting of the repository's non-Rust configuration files.
 ///
-/// # Returns
-/// Returns [`None`] if and only if it fails.
-fn run_ci_pipeline_rust_utils() -> Option<()> {
-    let _pkg_span = tracing::error_span!("Check `rust-utils`").entered();
-    let span = tracing::error_span!("Clippy").entered();
-    execute_command(
-        Command::new("cargo").args(["clippy", "--package", "rust-utils"]),
-        "Checking the source code",
-    )?;
-    span.exit();
-
-    let span = tracing::error_span!("Docs").entered();
-    execute_command(
-        Command::new("cargo").args(["doc", "--package", "rust-utils"]),
-        "Generating the public documentation.",
-    )?;
-    execute_command(
-        Command::new("cargo").args(["doc", "--package", "rust-utils", "--document-private-items"]),
-        "Generating the private documentation.",
-    )?;
-    span.exit();
-
-    let _span = tracing::error_span!("Format").entered();
-    execute_command(
-        Command::new("cargo").args(["fmt", "--check", "--package", "rust-utils"]),
-        "Checking the formatting of the code.",
-    )
+/// Declared [`exclusive`][Step::exclusive] because it rewrites the files it checks in place.
+struct FormatConfigs;
+
+impl Step for FormatConfigs {
+    fn name(&self) -> &str {
+        "FormatConfigs"
+    }
+
+    fn paths(&self) -> &[&str] {
+        &[
+            ".cargo/config.toml",
+            "Cargo.toml",
+            "rust-toolchain.toml",
+            ".github/workflows/ci.yml",
+            ".github/dependabot.yml",
+        ]
+    }
+
+    fn exclusive(&self) -> bool {
+        true
+    }
+
+    fn run(&self, ctx: &Context) -> Option<()> {
+        let _span = tracing::error_span!("FormatConfigs").entered();
+        run_buffered(ctx, |ctx| format_files(self.paths(), ctx.log_buffer))
+    }
 }